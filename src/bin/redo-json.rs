@@ -0,0 +1,12 @@
+use std::env;
+use std::process::Command;
+use std::os::unix::process::CommandExt;
+
+fn main() {
+    let mut cmd = Command::new("redonk");
+    cmd.arg("redojson");
+    cmd.args(env::args().skip(1));
+
+    let err = cmd.exec();
+    panic!("exec failed: {:?}", err);
+}
@@ -0,0 +1,236 @@
+// A pool of job tokens shared by every redonk (and make) process in a build,
+// using GNU make's jobserver protocol: a pipe or fifo holding one byte for
+// each job we may run on top of the one that every process implicitly holds.
+// It's advertised to child processes via `--jobserver-auth` in `MAKEFLAGS`,
+// so that a make run from a .do script shares our limit, and we share make's
+// when we're run from a Makefile.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use libc;
+
+use {Result, ResultExt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Auth {
+    // Make 4.2 and later: file descriptors we inherited.
+    Pipe(RawFd, RawFd),
+    // Make 4.4 and later: a named pipe.
+    Fifo(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Jobserver {
+    auth: Auth,
+    // The overall limit, if we know it.
+    jobs: Option<usize>,
+    // Our own views of the pipe; in particular, setting `O_NONBLOCK` on the
+    // inherited descriptor would change it for everyone else too.
+    reader: fs::File,
+    writer: fs::File,
+}
+
+// Returns its token to the pool when dropped.
+#[derive(Debug)]
+pub struct Token {
+    server: Arc<Jobserver>,
+    byte: u8,
+}
+
+impl Jobserver {
+    // Creates a pool allowing `jobs` jobs to run at once. The pipe is left
+    // inheritable, so that our children can get at it.
+    pub fn new(jobs: usize) -> Result<Jobserver> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Create jobserver pipe");
+        }
+        let server = Jobserver::open(Auth::Pipe(fds[0], fds[1]), Some(jobs))?;
+        for _ in 1..jobs {
+            server.release(b'+')?;
+        }
+        Ok(server)
+    }
+
+    // Joins the pool advertised by a make or redonk further up, if any.
+    pub fn from_env() -> Result<Option<Jobserver>> {
+        let flags = match env::var("MAKEFLAGS") {
+            Ok(flags) => flags,
+            Err(_) => return Ok(None),
+        };
+        let (auth, jobs) = parse_makeflags(&flags)?;
+        match auth {
+            Some(auth) => Jobserver::open(auth, jobs).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn open(auth: Auth, jobs: Option<usize>) -> Result<Jobserver> {
+        let (read_path, write_path) = match auth {
+            Auth::Pipe(r, w) => (fd_path(r), fd_path(w)),
+            Auth::Fifo(ref path) => (path.clone(), path.clone()),
+        };
+        // Make closes the descriptors for recipes that it doesn't think
+        // are recursive, so they may since have been reused for something else.
+        for path in &[&read_path, &write_path] {
+            let meta = fs::metadata(path).chain_err(|| format!("Jobserver {:?}", auth))?;
+            if !meta.file_type().is_fifo() {
+                return Err(format!("Jobserver {:?}: {:?} is not a pipe", auth, path).into());
+            }
+        }
+
+        let reader = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&read_path)
+            .chain_err(|| format!("Open jobserver {:?}", read_path))?;
+        let writer = fs::OpenOptions::new()
+            .write(true)
+            .open(&write_path)
+            .chain_err(|| format!("Open jobserver {:?}", write_path))?;
+        Ok(Jobserver {
+            auth,
+            jobs,
+            reader,
+            writer,
+        })
+    }
+
+    // Takes a token from the pool if one is free right now.
+    pub fn try_acquire(server: &Arc<Jobserver>) -> Result<Option<Token>> {
+        let mut buf = [0u8];
+        match (&server.reader).read(&mut buf) {
+            Ok(1) => Ok(Some(Token {
+                server: server.clone(),
+                byte: buf[0],
+            })),
+            Ok(_) => Ok(None),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e).chain_err(|| "Read jobserver token"),
+        }
+    }
+
+    // Make expects to get back the same byte that it handed out.
+    fn release(&self, byte: u8) -> Result<()> {
+        (&self.writer)
+            .write_all(&[byte])
+            .chain_err(|| "Return jobserver token")
+    }
+
+    // `MAKEFLAGS` for our children: whatever we were given, but pointing at
+    // this pool.
+    pub fn makeflags(&self, inherited: Option<&str>) -> String {
+        let mut words = Vec::new();
+        let mut overrides = Vec::new();
+        let mut in_overrides = false;
+        for word in inherited.unwrap_or("").split_whitespace() {
+            in_overrides = in_overrides || word == "--";
+            if in_overrides {
+                overrides.push(word.to_owned());
+            } else if !is_jobs_flag(word) {
+                words.push(word.to_owned());
+            }
+        }
+
+        words.push(match self.jobs {
+            Some(jobs) => format!("-j{}", jobs),
+            None => "-j".to_owned(),
+        });
+        words.push(match self.auth {
+            Auth::Pipe(r, w) => format!("--jobserver-auth={},{}", r, w),
+            Auth::Fifo(ref path) => format!("--jobserver-auth=fifo:{}", path.display()),
+        });
+        words.extend(overrides);
+        words.join(" ")
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if let Err(e) = self.server.release(self.byte) {
+            warn!("Lost a jobserver token: {}", e);
+        }
+    }
+}
+
+fn fd_path(fd: RawFd) -> PathBuf {
+    Path::new("/proc/self/fd").join(fd.to_string())
+}
+
+fn is_jobs_flag(word: &str) -> bool {
+    word.starts_with("-j") || word.starts_with("--jobs") || word.starts_with("--jobserver-")
+}
+
+// Picks out the jobserver and job limit from `MAKEFLAGS`; variable overrides
+// come after a `--`, and single letter flags may lack their leading dash.
+fn parse_makeflags(flags: &str) -> Result<(Option<Auth>, Option<usize>)> {
+    let mut auth = None;
+    let mut jobs = None;
+    for word in flags.split_whitespace().take_while(|&w| w != "--") {
+        let value = if let Some(value) = word.strip_prefix("--jobserver-auth=") {
+            value
+        } else if let Some(value) = word.strip_prefix("--jobserver-fds=") {
+            value
+        } else {
+            if let Some(n) = word.strip_prefix("-j") {
+                jobs = n.parse().ok();
+            } else if let Some(n) = word.strip_prefix("--jobs=") {
+                jobs = n.parse().ok();
+            }
+            continue;
+        };
+
+        auth = if let Some(path) = value.strip_prefix("fifo:") {
+            Some(Auth::Fifo(PathBuf::from(path)))
+        } else {
+            let fds = value
+                .split(',')
+                .map(|fd| fd.parse::<RawFd>())
+                .collect::<::std::result::Result<Vec<_>, _>>()
+                .chain_err(|| format!("Parse jobserver flag: {:?}", word))?;
+            match fds[..] {
+                [r, w] if r >= 0 && w >= 0 => Some(Auth::Pipe(r, w)),
+                // Make uses negative descriptors to say there's no jobserver.
+                [_, _] => None,
+                _ => return Err(format!("Parse jobserver flag: {:?}", word).into()),
+            }
+        };
+    }
+    Ok((auth, jobs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_makeflags, Auth};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_makeflags_should_find_pipe_jobserver() {
+        let (auth, jobs) = parse_makeflags("ks -j4 --jobserver-auth=3,4 -- FOO=bar").unwrap();
+        assert_eq!((auth, jobs), (Some(Auth::Pipe(3, 4)), Some(4)));
+    }
+
+    #[test]
+    fn parse_makeflags_should_find_fifo_jobserver() {
+        let (auth, jobs) = parse_makeflags(" -j --jobserver-auth=fifo:/tmp/GMfifo1").unwrap();
+        assert_eq!(
+            (auth, jobs),
+            (Some(Auth::Fifo(PathBuf::from("/tmp/GMfifo1"))), None)
+        );
+    }
+
+    #[test]
+    fn parse_makeflags_should_ignore_jobserver_after_overrides() {
+        let (auth, _) = parse_makeflags("-- X=--jobserver-auth=3,4").unwrap();
+        assert_eq!(auth, None);
+    }
+}
@@ -6,9 +6,13 @@ extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
 extern crate fs2;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate md5;
 extern crate rand;
+#[macro_use]
+extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -18,28 +22,45 @@ extern crate structopt;
 
 #[cfg(all(test, feature = "impl_trait"))]
 extern crate suppositions;
-#[cfg(all(test, feature = "impl_trait"))]
+#[cfg(test)]
 extern crate tempdir;
 
+mod jobserver;
+
 use std::path::{Component, Path, PathBuf};
-use std::process::{self, Command};
+use std::process::{self, Command, Stdio};
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::collections::VecDeque;
+use std::sync::{mpsc, Arc};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use fs2::FileExt;
 
+use rand::{Rng, SeedableRng, StdRng};
+use rusqlite::OptionalExtension;
 use structopt::StructOpt;
 
+use jobserver::Jobserver;
+
 const EXIT_SUCCESS: i32 = 0;
 const EXIT_FAILURE: i32 = 1;
 
+// Marks the point in a target's log at which a nested target was built; see
+// `Store::log_child`.
+const LOG_MARKER: &[u8] = b"@@REDO:";
+
 error_chain! {
     foreign_links {
         Io(::std::io::Error);
         Json(serde_json::Error);
+        Sqlite(rusqlite::Error);
     }
 }
 
@@ -48,7 +69,16 @@ arg_enum! {
     enum Operation {
         Redo,
         RedoIfChange,
-        RedoIfCreate
+        RedoIfCreate,
+        RedoAlways,
+        RedoStamp,
+        RedoOod,
+        RedoTargets,
+        RedoSources,
+        RedoWhichDo,
+        RedoLog,
+        RedoDot,
+        RedoJson
     }
 }
 
@@ -57,9 +87,40 @@ struct Opt {
     /// Important argument.
     #[structopt(raw(possible_values = "&Operation::variants()", case_insensitive = "true"))]
     op: Operation,
+    /// Rebuild targets even if they have been modified by hand.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+    /// Run up to this many jobs at once.
+    #[structopt(short = "j", long = "jobs")]
+    jobs: Option<usize>,
+    /// Carry on building other targets after one fails.
+    #[structopt(short = "k", long = "keep-going")]
+    keep_going: bool,
+    /// Build targets in a random order.
+    #[structopt(long = "shuffle")]
+    shuffle: bool,
+    /// Seed for `--shuffle`, to reproduce an earlier order.
+    #[structopt(long = "shuffle-seed")]
+    shuffle_seed: Option<u64>,
+    /// Pass the target's basename and extension as $1 and $2, as djb's
+    /// redo did.
+    #[structopt(long = "old-args")]
+    old_args: bool,
     targets: Vec<String>,
 }
 
+// Settings that apply to every target we build.
+#[derive(Debug, Clone)]
+struct Options {
+    xtrace: bool,
+    force: bool,
+    jobs: Option<usize>,
+    keep_going: bool,
+    // The seed to shuffle targets with, if we're doing so.
+    shuffle: Option<u64>,
+    old_args: bool,
+}
+
 #[derive(Debug)]
 struct FileSuffixTails<'a> {
     input: Option<&'a str>,
@@ -109,12 +170,106 @@ impl<'a> Iterator for FileSuffixTails<'a> {
     }
 }
 
-#[derive(Deserialize, Debug)]
+// Enough of a file's metadata to tell whether it has changed since we last
+// looked at it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Prereq {
+    name: PathBuf,
+    // The prerequisite's checksum (or `redo-stamp`) as of when the dependent
+    // was built.
+    checksum: Option<String>,
+}
+
+// Grosskurth's `i.type`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Kind {
+    Source,
+    #[default]
+    Target,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Item {
+    #[serde(skip)]
     name: PathBuf,
-    uptodate: Option<bool>,
+    #[serde(default)]
+    kind: Kind,
+    #[serde(default)]
+    prereqs: Vec<Prereq>,
+    // Files that must not exist for this target to remain up to date.
+    #[serde(default)]
+    prereqs_nonexist: Vec<PathBuf>,
+    // The `.do` file we used to build this; Grosskurth's `i.buildfile`.
+    #[serde(default)]
+    builder: Option<PathBuf>,
+    // Set by `redo-always`; this is never considered up to date.
+    #[serde(default)]
+    always: bool,
+    // The stat of the output of the last successful build (or for a source,
+    // when we last looked at it), if any. We only re-checksum the file if
+    // this changes.
+    #[serde(default)]
+    stat: Option<Stat>,
+    #[serde(default)]
+    checksum: Option<String>,
+    // Set by `redo-stamp`; if present, dependents look at this rather than
+    // the checksum of the output.
+    #[serde(default)]
+    stamp: Option<String>,
+    // The last runs (see `REDO_RUNID`) in which we checked this, saw it
+    // change, or failed to build it. Like the name, these are kept in columns
+    // of their own rather than in the serialised record.
+    #[serde(skip)]
+    checked_runid: Option<i64>,
+    #[serde(skip)]
+    changed_runid: Option<i64>,
+    #[serde(skip)]
+    failed_runid: Option<i64>,
+    // When we last tried to build this, in seconds since the epoch.
+    #[serde(default)]
+    built_at: Option<u64>,
+}
+
+// The columns of the `Files` table, in the order that `Store::item_of`
+// expects them.
+const FILE_COLUMNS: &str = "name, checked_runid, changed_runid, failed_runid, record";
+
+struct Store {
+    // The directory containing the `.redo` directory.
+    base: PathBuf,
+    // Identifies the top-level invocation we're part of.
+    runid: i64,
+    // Whether we were run by another invocation (and so share its run id),
+    // rather than being the top-level one.
+    nested: bool,
+    // Everything we know about the project, kept in `.redo/db.sqlite3`
+    // under the project's base directory.
+    db: rusqlite::Connection,
+    // Per-invocation memo of targets we've already brought up to date, and
+    // the checksum they ended up with; Grosskurth's `i.uptodate`.
+    uptodate: HashMap<PathBuf, Option<String>>,
+    // The targets we (and the invocations above us) are in the middle of
+    // building, outermost first; see `REDO_STACK`.
+    stack: Vec<PathBuf>,
+    // How many of the targets at the start of `stack` are being built by
+    // the invocations above us; we're only checking the rest so far.
+    inherited: usize,
+    // Log markers for targets that we built whilst checking one of ours,
+    // until we know whether it'll get a fresh log too; see `log_child`.
+    pending_logs: HashMap<PathBuf, Vec<PathBuf>>,
+    // Hands out tokens for building targets in parallel, if we've been asked
+    // to.
+    jobserver: Option<Arc<Jobserver>>,
+    // Where the stderr of .do scripts goes, besides their logs.
+    console: Arc<fs::File>,
 }
-struct Store;
 
 fn optionally_exists<T>(
     r: ::std::result::Result<T, io::Error>,
@@ -133,15 +288,132 @@ fn exists(path: &Path) -> Result<bool> {
     Ok(maybe_stat.is_some())
 }
 
+impl Stat {
+    fn of(path: &Path) -> Result<Option<Stat>> {
+        // A directory's contents aren't ours to track (eg: t/250-makedir), so
+        // as far as we are concerned, one directory looks like any other.
+        let stat = optionally_exists(fs::metadata(path))?.map(|m| if m.is_dir() {
+            Stat {
+                mtime: 0,
+                mtime_nsec: 0,
+                size: 0,
+            }
+        } else {
+            Stat {
+                mtime: m.st_mtime(),
+                mtime_nsec: m.st_mtime_nsec(),
+                size: m.st_size(),
+            }
+        });
+        Ok(stat)
+    }
+}
+
+// The MD5 of a file's contents, or `None` if it doesn't exist.
+fn checksum_of(path: &Path) -> Result<Option<String>> {
+    let mut ctx = md5::Context::new();
+    match optionally_exists(fs::File::open(path))? {
+        None => return Ok(None),
+        // Directories have no content of their own; see `Stat::of`.
+        Some(ref f) if f.metadata()?.is_dir() => {}
+        Some(mut f) => {
+            io::copy(&mut f, &mut ctx)
+                .chain_err(|| format!("Checksum {:?}", path))?;
+        }
+    }
+    Ok(Some(format!("{:x}", ctx.compute())))
+}
+
+// Makes `path` absolute with respect to the current directory, and removes
+// any `.` or `..` components, so that we have a stable name to key the store
+// by, no matter which directory a `.do` script happened to call us from.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let cwd = env::current_dir()?;
+    Ok(cwd.join(path).normalized())
+}
+
+// The current time, in whole seconds since the epoch.
+fn now() -> Result<u64> {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .chain_err(|| "Clock is before the epoch")?;
+    Ok(since.as_secs())
+}
+
+// The target whose `.do` script invoked us, if any; see `Builder::build_command`.
+fn parent_target() -> Option<PathBuf> {
+    let pwd = env::var_os("REDO_PWD")?;
+    let target = env::var_os("REDO_TARGET")?;
+    Some(Path::new(&pwd).join(target).normalized())
+}
+
+// See `Store::stack_env`.
+fn parse_stack(bytes: &[u8]) -> Vec<PathBuf> {
+    bytes
+        .split(|&b| b == b'\n')
+        .filter(|p| !p.is_empty())
+        .map(|p| PathBuf::from(OsStr::from_bytes(p)))
+        .collect()
+}
+
+// Given our stack, which ends with the target we're waiting for, and those
+// of everyone else who is waiting, looks for a chain of invocations waiting
+// on each other that leads back to one of ours. Anyone with a target on
+// their stack is part of the build of that target, and is waiting for the
+// last target on their stack.
+fn find_wait_cycle(stack: &[PathBuf], waits: &[Vec<PathBuf>]) -> Option<Vec<PathBuf>> {
+    let target = stack.last()?;
+    let mut seen = HashSet::new();
+    let mut todo = vec![vec![target.clone()]];
+    while let Some(chain) = todo.pop() {
+        let current = chain.last().expect("chain").clone();
+        if !seen.insert(current.clone()) {
+            continue;
+        }
+        for wait in waits {
+            let (waiting_for, building) = match wait.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+            let pos = match building.iter().position(|p| *p == current) {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let mut next = chain.clone();
+            next.extend(wait[pos + 1..].iter().cloned());
+            if let Some(start) = stack.iter().position(|p| p == waiting_for) {
+                let mut cycle = stack[start..stack.len() - 1].to_vec();
+                cycle.extend(next);
+                return Some(cycle);
+            }
+            todo.push(next);
+        }
+    }
+    None
+}
+
 impl Item {
     fn new_target(path: &Path) -> Self {
         Item {
             name: path.to_owned(),
-            uptodate: None,
+            kind: Kind::Target,
+            prereqs: Vec::new(),
+            prereqs_nonexist: Vec::new(),
+            builder: None,
+            always: false,
+            stat: None,
+            checksum: None,
+            stamp: None,
+            checked_runid: None,
+            changed_runid: None,
+            failed_runid: None,
+            built_at: None,
         }
     }
 
-    fn find_builder(&self) -> Result<Builder> {
+    // Any candidate `.do` files that we looked for but didn't find are added
+    // to `missing`, as creating one of those would change how we build.
+    fn find_builder(&self, missing: &mut Vec<PathBuf>) -> Result<Option<Builder>> {
         let cwd = Path::new(".").canonicalize()?;
 
         let mut path = cwd.join(&self.name);
@@ -152,14 +424,19 @@ impl Item {
             .to_owned();
 
         while path.pop() {
-            if let Some(builder) = self.search_target_in_dir(&fname, &path)? {
-                return Ok(builder);
+            if let Some(builder) = self.search_target_in_dir(&fname, &path, missing)? {
+                return Ok(Some(builder));
             }
         }
-        return Err(format!("Could not find builder for {:?}", self).into());
+        Ok(None)
     }
 
-    fn search_target_in_dir(&self, fname: &str, dir: &Path) -> Result<Option<Builder>> {
+    fn search_target_in_dir(
+        &self,
+        fname: &str,
+        dir: &Path,
+        missing: &mut Vec<PathBuf>,
+    ) -> Result<Option<Builder>> {
         for suffix in FileSuffixTails::new(&fname) {
             let is_default = suffix.is_empty() || suffix.chars().next() == Some('.');
             let name = format!("{}{}.do", if is_default { "default" } else { "" }, suffix);
@@ -170,20 +447,197 @@ impl Item {
             if exists(&candidate)? {
                 return Ok(Some(Builder::new(&candidate, is_default)?));
             };
+            missing.push(candidate);
         }
 
         Ok(None)
     }
 
-    fn is_target(&self) -> Result<bool> {
-        let res = !exists(&self.name)?;
-        debug!("is_target: {:?} → {:?}", self, res);
-        Ok(res)
+    fn is_target(&self) -> bool {
+        self.kind == Kind::Target
+    }
+
+    // A target that we've found a .do file for; as opposed to, say, a
+    // prerequisite that nobody knows how to make.
+    fn is_buildable(&self) -> bool {
+        self.is_target() && self.builder.is_some()
+    }
+
+    // Anything that exists when we first see it is a source, and anything
+    // else is a target. However, someone can take over a target that we
+    // never managed to build by creating it themselves, and a source that
+    // goes missing may well be generated from now on. Once we've started
+    // building a target with a `.do` file, it stays a target.
+    fn classify(&self) -> Result<Kind> {
+        let exists = exists(&self.name)?;
+        let never_built = self.builder.is_none() || self.failed_runid.is_some();
+        let kind = match self.kind {
+            Kind::Source if !exists => Kind::Target,
+            Kind::Target if exists && self.stat.is_none() && never_built => Kind::Source,
+            kind => kind,
+        };
+        Ok(kind)
+    }
+
+    // The checksum of the file as it stands, avoiding re-reading the file if
+    // it hasn't been touched since we last built it.
+    fn current_checksum(&self) -> Result<Option<String>> {
+        if self.stat.is_some() && Stat::of(&self.name)? == self.stat {
+            Ok(self.checksum.clone())
+        } else {
+            checksum_of(&self.name)
+        }
+    }
+
+    // The signature as of when we last built this.
+    fn recorded_signature(&self) -> Option<String> {
+        self.stamp.clone().or_else(|| self.checksum.clone())
     }
-    fn redo(&self, xtrace: bool) -> Result<()> {
-        if self.is_target()? {
+
+    // Whether someone has changed our output since we last built it. A
+    // deleted output is fair game to rebuild.
+    fn is_modified(&self) -> Result<bool> {
+        if !self.is_target() || self.stat.is_none() || !exists(&self.name)? {
+            return Ok(false);
+        }
+        Ok(self.current_checksum()? != self.checksum)
+    }
+
+    // What dependents should compare against to see whether we've changed.
+    fn signature(&self) -> Result<Option<String>> {
+        match self.stamp {
+            Some(ref stamp) => Ok(Some(stamp.clone())),
+            None => self.current_checksum(),
+        }
+    }
+
+    fn is_uptodate(&self, store: &mut Store, opts: &Options) -> Result<bool> {
+        if self.always {
+            debug!("{:?} is always rebuilt", self.name);
+            return Ok(false);
+        }
+
+        // Whatever is left over from a failed build doesn't count, even if
+        // nothing that it depends upon has changed since.
+        if self.failed_runid.is_some() {
+            debug!("{:?} failed to build last time", self.name);
+            return Ok(false);
+        }
+
+        // A target that has never been built is out of date; one that built
+        // nothing is fine for as long as there's still nothing there.
+        let current = self.current_checksum()?;
+        if self.builder.is_none() || current != self.checksum {
+            debug!(
+                "{:?} output changed: {:?} → {:?}",
+                self.name, self.checksum, current
+            );
+            return Ok(false);
+        }
+
+        for prereq in self.prereqs_nonexist.iter() {
+            if exists(prereq)? {
+                debug!("{:?} prereq {:?} was created", self.name, prereq);
+                return Ok(false);
+            }
+        }
+
+        for prereq in self.prereqs.iter() {
+            // A prerequisite that has vanished, and that we've never built,
+            // means that the dependent has to be rebuilt to find out whether
+            // it still needs it.
+            let built = store
+                .read(&prereq.name)?
+                .map(|p| p.is_buildable())
+                .unwrap_or(false);
+            if !exists(&prereq.name)? && !built {
+                debug!("{:?} prereq {:?} vanished", self.name, prereq.name);
+                return Ok(false);
+            }
+            let checksum = ifchange_one(store, &prereq.name, opts)?;
+            if checksum != prereq.checksum {
+                debug!(
+                    "{:?} prereq {:?} changed: {:?} → {:?}",
+                    self.name, prereq.name, prereq.checksum, checksum
+                );
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Like `is_uptodate`, but only consults what we've recorded, rather than
+    // bringing prerequisites up to date first. `memo` also stops us going
+    // round in circles.
+    fn is_ood(&self, store: &Store, memo: &mut HashMap<PathBuf, bool>) -> Result<bool> {
+        if let Some(&ood) = memo.get(&self.name) {
+            return Ok(ood);
+        }
+        memo.insert(self.name.clone(), false);
+        let ood = self.check_ood(store, memo)?;
+        memo.insert(self.name.clone(), ood);
+        Ok(ood)
+    }
+
+    fn check_ood(&self, store: &Store, memo: &mut HashMap<PathBuf, bool>) -> Result<bool> {
+        if self.always || self.builder.is_none() || self.failed_runid.is_some() {
+            return Ok(true);
+        }
+        if self.current_checksum()? != self.checksum {
+            return Ok(true);
+        }
+
+        for prereq in self.prereqs_nonexist.iter() {
+            if exists(prereq)? {
+                return Ok(true);
+            }
+        }
+
+        for prereq in self.prereqs.iter() {
+            let checksum = match store.read(&prereq.name)? {
+                Some(ref p) if p.is_buildable() => {
+                    if p.is_ood(store, memo)? {
+                        return Ok(true);
+                    }
+                    p.recorded_signature()
+                }
+                Some(ref p) => p.current_checksum()?,
+                None => checksum_of(&prereq.name)?,
+            };
+            if checksum != prereq.checksum {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn redo(&self, store: &mut Store, opts: &Options) -> Result<()> {
+        if self.is_target() {
             info!("Target: {:?}", self);
-            let dofile = self.find_builder()?;
+            let mut missing = Vec::new();
+            let dofile = match self.find_builder(&mut missing)? {
+                Some(dofile) => dofile,
+                None if exists(&self.name)? => {
+                    warn!("No builder for {:?}; treating it as a source", self.name);
+                    let mut source = Item::new_target(&self.name);
+                    source.kind = Kind::Source;
+                    return store.write(&source);
+                }
+                None => return Err(format!("Could not find builder for {:?}", self).into()),
+            };
+
+            if !opts.force && self.is_modified()? {
+                eprintln!(
+                    "redo: {:?} was modified outside of redo; leaving it alone \
+                     (use --force to rebuild it anyway)",
+                    self.name
+                );
+                let runid = store.runid;
+                return store.update(&self.name, |item| item.checked_runid = Some(runid));
+            }
+
             debug!(
                 "Build: {:?} with {:?} in {:?}",
                 self,
@@ -191,7 +645,58 @@ impl Item {
                 env::current_dir()
             );
 
-            dofile.perform(&self, xtrace).chain_err(|| "perform")?;
+            // The `.do` file is itself a prerequisite, and might well be
+            // generated.
+            let dofile_checksum = ifchange_one(store, &dofile.dofile, opts)?;
+
+            // Forget whatever we depended upon last time; the `.do` script
+            // will tell us about its prerequisites afresh as it runs. If the
+            // build fails, we put things back the way they were. Until then,
+            // the old output is still what's on disk.
+            let previous = store.read(&self.name)?;
+            let mut fresh = Item::new_target(&self.name);
+            if let Some(ref previous) = previous {
+                fresh.stat = previous.stat;
+                fresh.checksum = previous.checksum.clone();
+            }
+            fresh.prereqs.push(Prereq {
+                name: dofile.dofile.clone(),
+                checksum: dofile_checksum,
+            });
+            fresh.prereqs_nonexist = missing;
+            fresh.builder = Some(dofile.dofile.clone());
+            store.write(&fresh)?;
+
+            if let Some(parent) = store.parent_of(&self.name) {
+                store.log_child(&parent, &self.name)?;
+            }
+            store.start_log(&self.name)?;
+
+            let runid = store.runid;
+            if let Err(e) = dofile.perform(self, store, opts).chain_err(|| "perform") {
+                let mut failed = previous.unwrap_or_else(|| Item::new_target(&self.name));
+                failed.builder = Some(dofile.dofile.clone());
+                failed.failed_runid = Some(runid);
+                failed.built_at = Some(now()?);
+                store.write(&failed)?;
+                return Err(e);
+            }
+
+            let mut built = store
+                .read(&self.name)?
+                .unwrap_or_else(|| Item::new_target(&self.name));
+            built.stat = Stat::of(&self.name)?;
+            built.checksum = checksum_of(&self.name)?;
+            built.checked_runid = Some(runid);
+            let previous_signature = previous.as_ref().and_then(|p| p.recorded_signature());
+            built.changed_runid = if built.recorded_signature() != previous_signature {
+                Some(runid)
+            } else {
+                previous.and_then(|p| p.changed_runid)
+            };
+            built.failed_runid = None;
+            built.built_at = Some(now()?);
+            store.write(&built)?;
         } else {
             debug!("Presumed source file: {:?}", self);
         }
@@ -266,12 +771,39 @@ impl TempFile {
     }
 }
 
+// For clearing up after a build, where the script may have left either a
+// file or a directory.
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match optionally_exists(fs::symlink_metadata(path))? {
+        Some(ref meta) if meta.is_dir() => fs::remove_dir_all(path)?,
+        Some(_) => fs::remove_file(path)?,
+        None => (),
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 struct Builder {
     dofile: PathBuf,
     default: bool,
 }
 
+// Copies a .do script's stderr to its log, and on to the console; we carry on
+// logging even if the console has gone away.
+fn tee<R: Read>(mut input: R, mut log: fs::File, console: &fs::File) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        log.write_all(&buf[..n])?;
+        let _ = (&*console).write_all(&buf[..n]);
+    }
+}
+
 fn dot_if_empty(p: &Path) -> &Path {
     if p.as_os_str().is_empty() {
         Path::new(".")
@@ -335,22 +867,45 @@ impl Builder {
         Ok(OsStr::new(target_base))
     }
 
-    fn perform(&self, target: &Item, xtrace: bool) -> Result<()> {
+    fn perform(&self, target: &Item, store: &Store, opts: &Options) -> Result<()> {
         let target_abs = target.abs_path()?;
 
         let mut stdout_temp = target.tempfile()?;
         let mut named_temp = target.tempfile()?;
+        // The script creates $3 if it wants to; that's how we can tell
+        // whether it did.
+        fs::remove_file(&named_temp.path)?;
+        let before = Stat::of(&target_abs)?;
         debug!(
             "Target : {:?}",
             target_abs /* .components().collect::<Vec<_>>()*/
         );
 
-        let mut cmd = self.build_command(&target_abs, &mut stdout_temp, &mut named_temp, xtrace)?;
+        let mut cmd = self.build_command(
+            &target_abs,
+            &mut stdout_temp,
+            &mut named_temp,
+            store,
+            opts,
+        )?;
+        let log = store.open_log(&target.name)?;
         debug!("⇒ {:?} ({:?})", self.dofile, cmd);
-        let res = cmd.spawn()?.wait()?;
+        let mut child = cmd.spawn()?;
+        let stderr = child.stderr.take().expect("take child stderr");
+        let console = store.console.clone();
+        let tee = thread::spawn(move || tee(stderr, log, &console));
+        let res = child.wait()?;
+        tee.join()
+            .expect("join stderr tee")
+            .chain_err(|| format!("Log stderr of {:?}", target_abs))?;
         debug!("⇐ {:?}", self.dofile);
 
+        let discard = || -> Result<()> {
+            remove_if_exists(&stdout_temp.path)?;
+            remove_if_exists(&named_temp.path)
+        };
         if !res.success() {
+            discard()?;
             return Err(format!(
                 "Dofile: {:?} exited with code:{:?}",
                 self.dofile,
@@ -358,34 +913,46 @@ impl Builder {
             ).into());
         }
 
+        let after = Stat::of(&target_abs)?;
+        if after.is_some() && after != before {
+            discard()?;
+            return Err(format!(
+                "Dofile: {:?} modified {:?} directly; it should write to $3 or stdout",
+                self.dofile, target_abs
+            ).into());
+        }
+
         // The usptream version of t/250-del assumes that it's fine to delete
         // the target and create a directory with the same name.
 
-        // We should track whether someone has modified the target directly.
-
         let stdout_size = fs::metadata(&stdout_temp.path)?.len();
         // it's fine if someone wants to delete $3.
-        let named_size = optionally_exists(fs::metadata(&named_temp.path))?
-            .map(|s| s.len())
-            .unwrap_or(0);
+        let named = optionally_exists(fs::symlink_metadata(&named_temp.path))?;
 
         debug!("stdout: {:?} size:{:?}", &stdout_temp.path, stdout_size);
-        debug!("named: {:?} size:{:?}", &named_temp.path, named_size);
-
-        match (stdout_size, named_size) {
-            (0, 0) | (_, 0) => {
-                debug!("{:?} → {:?}", &stdout_temp.path, target);
-                fs::rename(stdout_temp.path, target.path())
-                    .chain_err(|| "Persist stdout tempfile")?;
+        debug!("named: {:?} {:?}", &named_temp.path, named);
+
+        let output = match (stdout_size, named.is_some()) {
+            (0, false) => None,
+            (0, true) => Some(&named_temp.path),
+            (_, false) => Some(&stdout_temp.path),
+            (_, true) => {
+                discard()?;
+                return Err(format!("Dofile: {:?} wrote to both stdout and $3", self.dofile).into());
             }
-            (0, _) => {
-                debug!("{:?} → {:?}", &named_temp.path, target);
-                fs::rename(named_temp.path, target.path()).chain_err(|| "Persist stdout tempfile")?;
+        };
+        match output {
+            Some(path) => {
+                debug!("{:?} → {:?}", path, target);
+                fs::rename(path, target.path()).chain_err(|| "Persist output tempfile")?;
             }
-            (_, _) => {
-                panic!("Both $3 and stdout written to!");
+            // Producing nothing at all means there should be no target.
+            None => {
+                debug!("No output for {:?}", target);
+                remove_if_exists(target.path())?;
             }
         }
+        remove_if_exists(&stdout_temp.path)?;
 
         Ok(())
     }
@@ -395,7 +962,8 @@ impl Builder {
         target_abs: &Path,
         stdout: &mut TempFile,
         named_temp: &mut TempFile,
-        xtrace: bool,
+        store: &Store,
+        opts: &Options,
     ) -> Result<Command> {
         let builder_abs = self.dofile.canonicalize()?;
 
@@ -428,26 +996,62 @@ impl Builder {
         } else {
             let mut cmd = Command::new("sh");
             cmd.arg("-e");
-            if xtrace {
+            if opts.xtrace {
                 cmd.arg("-x");
             };
             cmd.arg(&self.dofile);
             cmd
         };
 
+        if opts.old_args {
+            // The extension matched by a `default.*.do`, if any.
+            let ext = OsStr::from_bytes(&target_name.as_os_str().as_bytes()[target_base.len()..]);
+            cmd
+                // $1: Basename of the target
+                .arg(target_base)
+                // $2: Extension of the target
+                .arg(ext);
+        } else {
+            cmd
+                // $1: Target name
+                .arg(&target_name)
+                // $2: Basename of the target
+                .arg(target_base);
+        }
         cmd
-            // $1: Target name
-            .arg(&target_name)
-            // $2: Basename of the target
-            .arg(&target_base)
             // $3: temporary output file.
             .arg(named_temp.path.relative_to_dir(&builder_dir));
         cmd.current_dir(builder_dir);
 
         cmd.stdout(stdout.file.take().expect("take stdout temp file"));
-
-        // Emulate apenwarr's minimal/do
-        cmd.env("DO_BUILT", "t");
+        // Copied to the target's log; see `tee`.
+        cmd.stderr(Stdio::piped());
+
+        // So that nested invocations can record their dependencies against
+        // this target; see `parent_target`.
+        cmd.env("REDO_PWD", builder_dir);
+        cmd.env("REDO_TARGET", &target_name);
+        cmd.env("REDO_RUNID", store.runid.to_string());
+        cmd.env("REDO_BASE", &store.base);
+        cmd.env("REDO_STACK", store.stack_env());
+        cmd.env("REDO_STDERR_FD", store.console.as_raw_fd().to_string());
+        if opts.force {
+            cmd.env("REDO_FORCE", "t");
+        }
+        if opts.keep_going {
+            cmd.env("REDO_KEEP_GOING", "t");
+        }
+        if let Some(seed) = opts.shuffle {
+            cmd.env("REDO_SHUFFLE_SEED", seed.to_string());
+        }
+        if opts.old_args {
+            cmd.env("REDO_OLD_ARGS", "t");
+        }
+        // Shared with nested invocations, and any `make` the script runs.
+        if let Some(ref jobserver) = store.jobserver {
+            let inherited = env::var("MAKEFLAGS").ok();
+            cmd.env("MAKEFLAGS", jobserver.makeflags(inherited.as_deref()));
+        }
 
         Ok(cmd)
     }
@@ -463,55 +1067,1024 @@ impl Builder {
 }
 
 impl Store {
-    fn new() -> Result<Self> {
-        Ok(Store)
+    // For building: we start a project here if we can't find one, and a run
+    // of our own unless we're part of one already.
+    fn new(runid: Option<i64>, jobserver: Option<Jobserver>) -> Result<Self> {
+        let base = match Store::find_base()? {
+            Some(base) => base,
+            None => env::current_dir()?,
+        };
+        debug!("base: {:?}", base);
+        let db = Store::open_db(&base, true)?;
+        let nested = runid.is_some();
+        let runid = match runid {
+            Some(runid) => runid,
+            None => Store::new_runid(&db)?,
+        };
+        Store::with_db(base, db, runid, nested, jobserver)
+    }
+
+    // For looking at what earlier builds have left behind, without leaving
+    // anything of our own; so we take the latest run as ours, rather than
+    // starting another.
+    fn existing(runid: Option<i64>) -> Result<Self> {
+        let base = match Store::find_base()? {
+            Some(base) => base,
+            None => {
+                let cwd = env::current_dir()?;
+                return Err(format!("No .redo directory in {:?} or above it", cwd).into());
+            }
+        };
+        debug!("base: {:?}", base);
+        let db = Store::open_db(&base, false)?;
+        let nested = runid.is_some();
+        let runid = match runid {
+            Some(runid) => runid,
+            None => Store::latest_runid(&db)?,
+        };
+        Store::with_db(base, db, runid, nested, None)
+    }
+
+    fn with_db(
+        base: PathBuf,
+        db: rusqlite::Connection,
+        runid: i64,
+        nested: bool,
+        jobserver: Option<Jobserver>,
+    ) -> Result<Self> {
+        debug!("runid: {:?}", runid);
+        let uptodate = HashMap::new();
+        let stack = env::var_os("REDO_STACK")
+            .map(|s| parse_stack(s.as_bytes()))
+            .unwrap_or_default();
+        Ok(Store {
+            base,
+            runid,
+            nested,
+            db,
+            uptodate,
+            inherited: stack.len(),
+            stack,
+            pending_logs: HashMap::new(),
+            jobserver: jobserver.map(Arc::new),
+            console: Arc::new(Store::open_console()?),
+        })
+    }
+
+    // Nested invocations write to the top-level's stderr directly, rather
+    // than via the .do script that ran them, so that their output doesn't end
+    // up in their parent's log as well as their own.
+    fn open_console() -> Result<fs::File> {
+        let inherited = env::var("REDO_STDERR_FD")
+            .ok()
+            .and_then(|fd| fd.parse::<RawFd>().ok());
+        if let Some(fd) = inherited {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1 {
+                return Ok(unsafe { fs::File::from_raw_fd(fd) });
+            }
+            warn!("Ignoring closed REDO_STDERR_FD: {}", fd);
+        }
+        // Left inheritable, so that nested invocations can find it.
+        let fd = unsafe { libc::dup(2) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).chain_err(|| "Duplicate stderr");
+        }
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    // A store for building one of our targets on another thread; it shares
+    // everything but the database connection and the in-memory state.
+    fn fork(&self) -> Result<Store> {
+        Ok(Store {
+            base: self.base.clone(),
+            runid: self.runid,
+            nested: self.nested,
+            db: Store::open_db(&self.base, false)?,
+            uptodate: self.uptodate.clone(),
+            stack: self.stack.clone(),
+            inherited: self.inherited,
+            pending_logs: HashMap::new(),
+            jobserver: self.jobserver.clone(),
+            console: self.console.clone(),
+        })
+    }
+
+    // Notes that we're working on `target`, unless that would mean going
+    // round in circles.
+    fn enter(&mut self, target: &Path) -> Result<()> {
+        if let Some(start) = self.stack.iter().position(|p| p == target) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(target.to_owned());
+            return Err(self.cycle_error(&cycle));
+        }
+        self.stack.push(target.to_owned());
+        Ok(())
+    }
+
+    fn cycle_error(&self, cycle: &[PathBuf]) -> Error {
+        let names = cycle
+            .iter()
+            .map(|p| p.strip_prefix(&self.base).unwrap_or(p).display().to_string())
+            .collect::<Vec<_>>();
+        format!("Dependency cycle: {}", names.join(" → ")).into()
+    }
+
+    // If we didn't rebuild the target after all, then whatever we built
+    // whilst checking it was really on behalf of whoever asked us to.
+    fn leave(&mut self) {
+        let children = self.stack
+            .pop()
+            .and_then(|target| self.pending_logs.remove(&target))
+            .unwrap_or_default();
+        if let Some(parent) = self.stack.last().cloned() {
+            for child in children {
+                if let Err(e) = self.log_child(&parent, &child) {
+                    warn!("Could not log {:?} in {:?}: {}", child, parent, e);
+                }
+            }
+        }
+    }
+
+    // The target whose check led us to `target`; this may be in this very
+    // invocation, rather than the one whose `.do` script ran us.
+    fn parent_of(&self, target: &Path) -> Option<PathBuf> {
+        let pos = self.stack.iter().rposition(|p| p == target)?;
+        self.stack[..pos].last().cloned()
+    }
+
+    // Newline separated, as that's rather less likely to turn up in a file
+    // name than a colon.
+    fn stack_env(&self) -> OsString {
+        let mut bytes = Vec::new();
+        for p in self.stack.iter() {
+            bytes.extend_from_slice(p.as_os_str().as_bytes());
+            bytes.push(b'\n');
+        }
+        OsString::from_vec(bytes)
+    }
+
+    // Nested invocations are told where the project lives by `REDO_BASE`;
+    // otherwise, we look for an existing `.redo` directory in the current
+    // directory or its ancestors.
+    fn find_base() -> Result<Option<PathBuf>> {
+        if let Some(base) = env::var_os("REDO_BASE") {
+            return Ok(Some(PathBuf::from(base)));
+        }
+
+        let mut dir = env::current_dir()?;
+        loop {
+            if exists(&dir.join(".redo"))? {
+                return Ok(Some(dir));
+            }
+            if !dir.pop() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn db_dir(&self) -> PathBuf {
+        self.base.join(".redo")
+    }
+
+    // Concurrent invocations each have a connection of their own, and rely
+    // on sqlite's locking to keep out of each other's way. Run ids have
+    // columns of their own, so that `t/flush-cache` can get at them. Unless
+    // asked to `create` it, the database must exist already.
+    fn open_db(base: &Path, create: bool) -> Result<rusqlite::Connection> {
+        let dir = base.join(".redo");
+        let mut flags = rusqlite::OpenFlags::default();
+        if create {
+            fs::create_dir_all(&dir)?;
+        } else {
+            flags.remove(rusqlite::OpenFlags::SQLITE_OPEN_CREATE);
+        }
+        let path = dir.join("db.sqlite3");
+        let db = rusqlite::Connection::open_with_flags(&path, flags)
+            .chain_err(|| format!("Open database {:?}", path))?;
+        db.busy_timeout(Duration::from_secs(60))?;
+        db.execute_batch(
+            "pragma journal_mode = wal;
+             pragma synchronous = normal;
+             create table if not exists Runid (
+                 id integer primary key autoincrement
+             );
+             create table if not exists Files (
+                 name blob primary key,
+                 checked_runid integer,
+                 changed_runid integer,
+                 failed_runid integer,
+                 record text not null
+             );",
+        ).chain_err(|| format!("Initialise database {:?}", path))?;
+        Ok(db)
+    }
+
+    fn new_runid(db: &rusqlite::Connection) -> Result<i64> {
+        db.execute("insert into Runid default values", rusqlite::NO_PARAMS)?;
+        Ok(db.last_insert_rowid())
+    }
+
+    fn latest_runid(db: &rusqlite::Connection) -> Result<i64> {
+        let runid = db.query_row(
+            "select coalesce(max(id), 0) from Runid",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        Ok(runid)
+    }
+
+    // Names the files we keep alongside the database for `target`.
+    fn file_key(target: &Path) -> String {
+        format!("{:x}", md5::compute(target.as_os_str().as_bytes()))
+    }
+
+    fn log_path(&self, target: &Path) -> PathBuf {
+        self.db_dir().join("log").join(Store::file_key(target))
+    }
+
+    // We append, as nested invocations add to the log too; see `log_child`.
+    fn open_log(&self, target: &Path) -> Result<fs::File> {
+        let path = self.log_path(target);
+        fs::create_dir_all(self.db_dir().join("log"))?;
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .chain_err(|| format!("Open log {:?} for {:?}", path, target))
+    }
+
+    // Starts a fresh log for a build of `target`, beginning with whatever we
+    // built whilst deciding to rebuild it.
+    fn start_log(&mut self, target: &Path) -> Result<()> {
+        let mut log = self.open_log(target)?;
+        log.set_len(0)?;
+        for child in self.pending_logs.remove(target).unwrap_or_default() {
+            Store::write_log_marker(&mut log, &child)?;
+        }
+        Ok(())
+    }
+
+    // Notes in `parent`'s log that we're building `child` on its behalf, so
+    // that `redo-log` can show the child's log at that point. If we're only
+    // checking `parent` as yet, the note waits for `start_log` or `leave`.
+    fn log_child(&mut self, parent: &Path, child: &Path) -> Result<()> {
+        if !self.stack[..self.inherited].iter().any(|p| p == parent) {
+            self.pending_logs
+                .entry(parent.to_owned())
+                .or_default()
+                .push(child.to_owned());
+            return Ok(());
+        }
+        let mut log = self.open_log(parent)?;
+        Store::write_log_marker(&mut log, child)
+    }
+
+    fn write_log_marker(log: &mut fs::File, child: &Path) -> Result<()> {
+        let mut line = LOG_MARKER.to_vec();
+        line.extend_from_slice(child.as_os_str().as_bytes());
+        line.push(b'\n');
+        log.write_all(&line)?;
+        Ok(())
+    }
+
+    // Held for as long as we're checking or building `target`, so that
+    // concurrent invocations never build the same target at once.
+    fn lock_target(&self, target: &Path) -> Result<fs::File> {
+        let dir = self.db_dir().join("locks");
+        fs::create_dir_all(&dir)?;
+        let lockf = dir.join(Store::file_key(target));
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lockf)
+            .chain_err(|| format!("Open lock file {:?} for {:?}", lockf, target))?;
+        if lock.try_lock_exclusive().is_err() {
+            debug!("Waiting for lock on {:?}", target);
+            let (note, note_path) = self.note_wait()?;
+            let res = self.check_waits().and_then(|()| Ok(lock.lock_exclusive()?));
+            drop(note);
+            fs::remove_file(&note_path)?;
+            res?;
+        }
+        Ok(lock)
+    }
+
+    // Leaves a note of our stack (which ends with the target we're after)
+    // for others to find whilst we wait for a target's lock. We hold a lock
+    // on the note itself, so that any left behind by invocations that have
+    // since died are ignored; we take it before writing anything, so that
+    // nobody mistakes a note that we're still writing for one of those.
+    fn note_wait(&self) -> Result<(fs::File, PathBuf)> {
+        let dir = self.db_dir().join("waits");
+        fs::create_dir_all(&dir)?;
+        loop {
+            let path = dir.join(format!("{}-{:x}", process::id(), rand::random::<u64>()));
+            let open = fs::OpenOptions::new().write(true).create_new(true).open(&path);
+            let mut note = match open {
+                Ok(note) => note,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).chain_err(|| format!("Create wait note {:?}", path)),
+            };
+            note.lock_exclusive()?;
+            note.write_all(self.stack_env().as_bytes())?;
+            return Ok((note, path));
+        }
+    }
+
+    // The stacks of everyone who is currently waiting for a target's lock.
+    fn waits(&self) -> Result<Vec<Vec<PathBuf>>> {
+        let mut waits = Vec::new();
+        for entry in fs::read_dir(self.db_dir().join("waits"))? {
+            let path = entry?.path();
+            let mut note = match optionally_exists(fs::File::open(&path))? {
+                Some(note) => note,
+                None => continue,
+            };
+            if note.try_lock_shared().is_ok() {
+                continue;
+            }
+            let mut bytes = Vec::new();
+            note.read_to_end(&mut bytes)?;
+            waits.push(parse_stack(&bytes));
+        }
+        Ok(waits)
+    }
+
+    // Our own stack only covers the invocations above us; to notice a cycle
+    // that runs through other branches of a parallel build, we need to see
+    // what everyone else is waiting for.
+    fn check_waits(&self) -> Result<()> {
+        match find_wait_cycle(&self.stack, &self.waits()?) {
+            Some(cycle) => Err(self.cycle_error(&cycle)),
+            None => Ok(()),
+        }
+    }
+
+    fn item_of(row: &rusqlite::Row) -> rusqlite::Result<(Vec<u8>, Item)> {
+        let mut item: Item = match serde_json::from_str(&row.get::<_, String>(4)?) {
+            Ok(item) => item,
+            Err(e) => {
+                let kind = rusqlite::types::Type::Text;
+                return Err(rusqlite::Error::FromSqlConversionFailure(4, kind, Box::new(e)));
+            }
+        };
+        item.checked_runid = row.get(1)?;
+        item.changed_runid = row.get(2)?;
+        item.failed_runid = row.get(3)?;
+        Ok((row.get(0)?, item))
     }
 
-    fn state_file_of(&self, name: &Path) -> Result<PathBuf> {
-        let fname = name.file_name()
-            .and_then(|s| s.to_str())
-            .expect("PathBuf::file_name");
-        let state_fname = format!(".redonk.{}", fname);
-        Ok(name.with_file_name(state_fname))
+    fn items(&self) -> Result<Vec<Item>> {
+        let mut stmt = self.db
+            .prepare_cached(&format!("select {} from Files", FILE_COLUMNS))?;
+        let mut items = stmt.query_map(rusqlite::NO_PARAMS, Store::item_of)?
+            .map(|row| {
+                let (name, mut item) = row?;
+                item.name = PathBuf::from(OsString::from_vec(name));
+                Ok(item)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(items)
     }
 
     fn read(&self, name: &Path) -> Result<Option<Item>> {
-        let state_file = self.state_file_of(name)?;
-        if let Some(r) = optionally_exists(fs::File::open(&state_file))? {
-            let res = serde_json::from_reader(r)?;
-            Ok(Some(res))
+        let mut stmt = self.db
+            .prepare_cached(&format!("select {} from Files where name = ?", FILE_COLUMNS))?;
+        let found = stmt.query_row(params![name.as_os_str().as_bytes()], Store::item_of)
+            .optional()?;
+        Ok(found.map(|(_, mut item)| {
+            item.name = name.to_owned();
+            item
+        }))
+    }
+
+    fn write(&self, item: &Item) -> Result<()> {
+        let record = serde_json::to_string(item)?;
+        let mut stmt = self.db.prepare_cached(&format!(
+            "insert or replace into Files ({}) values (?, ?, ?, ?, ?)",
+            FILE_COLUMNS
+        ))?;
+        stmt.execute(params![
+            item.name.as_os_str().as_bytes(),
+            item.checked_runid,
+            item.changed_runid,
+            item.failed_runid,
+            record,
+        ])?;
+        Ok(())
+    }
+
+    // Runs `f` whilst holding the database's write lock, so that concurrent
+    // invocations never lose each other's updates.
+    fn transaction<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        self.db.execute_batch("begin immediate")?;
+        let res = f();
+        let end = if res.is_ok() { "commit" } else { "rollback" };
+        self.db.execute_batch(end)?;
+        res
+    }
+
+    // Looks up what we know about `name`, deciding whether it's a source or
+    // a target if we've not seen it before, or if it has changed hands.
+    fn classify(&self, name: &Path) -> Result<Item> {
+        let (known, item) = match self.read(name)? {
+            Some(item) => (true, item),
+            None => (false, Item::new_target(name)),
+        };
+        let kind = item.classify()?;
+        if known && kind == item.kind {
+            return Ok(item);
+        }
+
+        debug!("{:?} is a {:?} (was: {:?})", name, kind, item.kind);
+        let mut fresh = Item::new_target(name);
+        fresh.kind = kind;
+        self.write(&fresh)?;
+        Ok(fresh)
+    }
+
+    // Everything that failed to build in this run, by us or by anyone else.
+    fn failed(&self) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.db
+            .prepare_cached("select name from Files where failed_runid = ? order by name")?;
+        let names = stmt.query_map(params![self.runid], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|name| Ok(PathBuf::from(OsString::from_vec(name?))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(names)
+    }
+
+    fn signature_of(&self, name: &Path) -> Result<Option<String>> {
+        self.read(name)?
+            .unwrap_or_else(|| Item::new_target(name))
+            .signature()
+    }
+
+    fn update<F: FnOnce(&mut Item)>(&self, name: &Path, f: F) -> Result<()> {
+        self.transaction(|| {
+            let mut item = self.read(name)?
+                .unwrap_or_else(|| Item::new_target(name));
+            f(&mut item);
+            self.write(&item)
+        })
+    }
+
+    fn add_prereq(&self, parent: &Path, prereq: Prereq) -> Result<()> {
+        debug!("{:?} depends on {:?}", parent, prereq);
+        self.update(parent, |item| {
+            item.prereqs.retain(|p| p.name != prereq.name);
+            item.prereqs.push(prereq);
+        })
+    }
+
+    fn add_prereq_nonexist(&self, parent: &Path, prereq: &Path) -> Result<()> {
+        debug!("{:?} depends on {:?} not existing", parent, prereq);
+        self.update(parent, |item| {
+            if !item.prereqs_nonexist.iter().any(|p| p == prereq) {
+                item.prereqs_nonexist.push(prereq.to_owned());
+            }
+        })
+    }
+
+    fn mark_always(&self, name: &Path) -> Result<()> {
+        debug!("{:?} is always out of date", name);
+        self.update(name, |item| item.always = true)
+    }
+
+    fn set_stamp(&self, name: &Path, stamp: String) -> Result<()> {
+        debug!("{:?} stamped with {:?}", name, stamp);
+        self.update(name, |item| item.stamp = Some(stamp))
+    }
+}
+
+fn redo(store: &mut Store, targets: &[PathBuf], opts: &Options) -> Result<()> {
+    let parent = parent_target();
+    let targets = targets
+        .iter()
+        .map(|t| absolute(t))
+        .collect::<Result<Vec<_>>>()?;
+    let checksums = build_all(store, &targets, opts, redo_one)?;
+
+    if let Some(ref parent) = parent {
+        for (target, checksum) in targets.into_iter().zip(checksums) {
+            store.add_prereq(parent, Prereq { name: target, checksum })?;
+        }
+    }
+
+    Ok(())
+}
+
+// Unlike `redo-ifchange`, we build the target regardless of whether it's up
+// to date.
+fn redo_one(store: &mut Store, target: &Path, opts: &Options) -> Result<Option<String>> {
+    store.enter(target)?;
+    let res = store
+        .lock_target(target)
+        .and_then(|_lock| store.classify(target)?.redo(store, opts));
+    store.leave();
+    res?;
+
+    let checksum = store.signature_of(target)?;
+    store.uptodate.insert(target.to_owned(), checksum.clone());
+    Ok(checksum)
+}
+
+// Runs `build` over each of `targets`, and returns the checksums they ended
+// up with. Without a jobserver, we just go through them in order; otherwise,
+// we build the first using the token we implicitly hold, and start another
+// thread for each further token we can get hold of. We stop starting new
+// targets after the first failure, unless asked to keep going.
+fn build_all(
+    store: &mut Store,
+    targets: &[PathBuf],
+    opts: &Options,
+    build: fn(&mut Store, &Path, &Options) -> Result<Option<String>>,
+) -> Result<Vec<Option<String>>> {
+    let mut checksums = vec![None; targets.len()];
+    let mut failures = Vec::new();
+    let mut order = (0..targets.len()).collect::<Vec<_>>();
+    if let Some(seed) = opts.shuffle {
+        shuffle_rng(seed, targets).shuffle(&mut order);
+        debug!("Shuffled order: {:?}", order);
+    }
+
+    match store.jobserver.clone() {
+        Some(ref jobserver) if targets.len() > 1 => build_parallel(
+            store,
+            jobserver,
+            order.iter().map(|&idx| (idx, targets[idx].clone())).collect(),
+            opts,
+            build,
+            &mut checksums,
+            &mut failures,
+        )?,
+        _ => for &idx in order.iter() {
+            let target = &targets[idx];
+            match build(store, target, opts) {
+                Ok(checksum) => checksums[idx] = checksum,
+                Err(e) => {
+                    failures.push((target.clone(), e));
+                    if !opts.keep_going {
+                        break;
+                    }
+                }
+            }
+        },
+    }
+
+    if failures.is_empty() {
+        Ok(checksums)
+    } else if !opts.keep_going {
+        Err(failures.remove(0).1)
+    } else if store.nested {
+        Err(format!("{} of {} targets failed", failures.len(), targets.len()).into())
+    } else {
+        // Nested invocations leave the summary to us, so that it comes at the
+        // very end, and covers everything that failed along the way.
+        let failed = store.failed()?;
+        let summary = match failed.len() {
+            1 => "1 target failed".to_owned(),
+            n => format!("{} targets failed", n),
+        };
+        eprintln!("{}:", summary);
+        for target in failed {
+            let name = target.strip_prefix(&store.base).unwrap_or(&target).to_owned();
+            eprintln!("  {}", name.display());
+        }
+        Err(summary.into())
+    }
+}
+
+// Nested invocations share the seed, so we mix in the targets we've been
+// given; otherwise every list of the same length would be shuffled alike.
+fn shuffle_rng(seed: u64, targets: &[PathBuf]) -> StdRng {
+    let mut ctx = md5::Context::new();
+    ctx.consume(seed.to_string());
+    for target in targets {
+        ctx.consume(target.as_os_str().as_bytes());
+        ctx.consume(b"\0");
+    }
+    let digest = ctx.compute();
+    let words = digest
+        .chunks(8)
+        .map(|c| c.iter().fold(0u64, |w, &b| w << 8 | b as u64) as usize)
+        .collect::<Vec<_>>();
+    StdRng::from_seed(&words[..])
+}
+
+fn build_parallel(
+    store: &mut Store,
+    jobserver: &Arc<Jobserver>,
+    mut pending: VecDeque<(usize, PathBuf)>,
+    opts: &Options,
+    build: fn(&mut Store, &Path, &Options) -> Result<Option<String>>,
+    checksums: &mut [Option<String>],
+    failures: &mut Vec<(PathBuf, Error)>,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut running = 0;
+    let mut implicit_free = true;
+
+    loop {
+        let stopping = !failures.is_empty() && !opts.keep_going;
+        while !stopping && !pending.is_empty() {
+            let token = if implicit_free {
+                implicit_free = false;
+                None
+            } else {
+                match Jobserver::try_acquire(jobserver)? {
+                    Some(token) => Some(token),
+                    None => break,
+                }
+            };
+
+            let (idx, target) = pending.pop_front().expect("pending target");
+            debug!("Starting job for {:?} (token: {:?})", target, token);
+            let mut child = store.fork()?;
+            let opts = opts.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                // Otherwise, we'd wait forever for this job to finish.
+                let job = AssertUnwindSafe(|| build(&mut child, &target, &opts));
+                let res = panic::catch_unwind(job).unwrap_or_else(|_| {
+                    Err(format!("Panicked whilst building {:?}", target).into())
+                });
+                let implicit = token.is_none();
+                drop(token);
+                let _ = tx.send((idx, target, implicit, res, child.uptodate));
+            });
+            running += 1;
+        }
+
+        if running == 0 {
+            return Ok(());
+        }
+
+        // Whilst we have targets waiting, keep an eye out for tokens that
+        // other processes have given back.
+        let msg = if !stopping && !pending.is_empty() {
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(msg) => msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => unreachable!(),
+            }
         } else {
-            Ok(None)
+            rx.recv().expect("job result")
+        };
+
+        let (idx, target, implicit, res, uptodate) = msg;
+        running -= 1;
+        implicit_free = implicit_free || implicit;
+        store.uptodate.extend(uptodate);
+        match res {
+            Ok(checksum) => checksums[idx] = checksum,
+            Err(e) => failures.push((target, e)),
         }
     }
 }
 
-fn redo(store: &mut Store, targets: &[PathBuf], xtrace: bool) -> Result<()> {
-    // Mark targets as non-up to date
-    redo_ifchange(store, targets, xtrace)
+// Follows Grosskurth's algorithm (see notes.md): a target is rebuilt if it
+// has never been built, if its output has changed since, or if any of the
+// prerequisites it recorded last time have changed once brought up to date
+// themselves. Sources are merely checksummed. We refuse to build anything that
+// is already being built further up the stack, to avoid loops.
+fn redo_ifchange(store: &mut Store, targets: &[PathBuf], opts: &Options) -> Result<()> {
+    let parent = parent_target();
+    let targets = targets
+        .iter()
+        .map(|t| absolute(t))
+        .collect::<Result<Vec<_>>>()?;
+    let checksums = build_all(store, &targets, opts, ifchange_one)?;
+
+    if let Some(ref parent) = parent {
+        for (target, checksum) in targets.into_iter().zip(checksums) {
+            store.add_prereq(parent, Prereq { name: target, checksum })?;
+        }
+    }
+
+    Ok(())
+}
+
+// Brings `target` up to date, and returns the checksum that dependents should
+// compare against next time.
+fn ifchange_one(store: &mut Store, target: &Path, opts: &Options) -> Result<Option<String>> {
+    if let Some(checksum) = store.uptodate.get(target) {
+        trace!("Already checked: {:?}", target);
+        return Ok(checksum.clone());
+    }
+
+    store.enter(target)?;
+    let res = check_and_build(store, target, opts);
+    store.leave();
+    res?;
+
+    let checksum = store.signature_of(target)?;
+    store.uptodate.insert(target.to_owned(), checksum.clone());
+    Ok(checksum)
+}
+
+fn check_and_build(store: &mut Store, target: &Path, opts: &Options) -> Result<()> {
+    // Whoever held the lock before us may well have just built it, so we
+    // only look at what we know about it once we have the lock.
+    let _lock = store.lock_target(target)?;
+    let it = store.classify(target)?;
+
+    // There's no point running a broken `.do` script again; but `redo` will
+    // still give it another go.
+    let runid = store.runid;
+    if it.failed_runid == Some(runid) {
+        return Err(format!("Dependency {:?} failed earlier in this run", target).into());
+    }
+
+    if it.checked_runid == Some(runid) {
+        debug!("Already checked in this run: {:?}", it);
+    } else if !it.is_target() {
+        debug!("Source file: {:?}", it);
+        let stat = Stat::of(target)?;
+        let checksum = it.current_checksum()?;
+        store.update(target, |item| {
+            if item.checksum != checksum {
+                item.changed_runid = Some(runid);
+            }
+            item.stat = stat;
+            item.checksum = checksum;
+            item.checked_runid = Some(runid);
+        })?;
+    } else if it.is_uptodate(store, opts)? {
+        debug!("Up to date: {:?}", it);
+        store.update(target, |item| item.checked_runid = Some(runid))?;
+    } else {
+        it.redo(store, opts)?;
+    }
+
+    Ok(())
+}
+
+fn redo_ifcreate(store: &mut Store, targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let parent = parent_target();
+    for target in targets {
+        let target = absolute(target)?;
+        if exists(&target)? {
+            return Err(format!("redo-ifcreate: {:?} already exists", target).into());
+        }
+
+        if let Some(ref parent) = parent {
+            store.add_prereq_nonexist(parent, &target)?;
+        }
+    }
+    Ok(())
+}
+
+fn redo_always(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let parent = parent_target().chain_err(|| "redo-always must be run from a .do script")?;
+    store.mark_always(&parent)
+}
+
+// Reads data from stdin, and uses its checksum to decide whether the target
+// that is currently being built has changed, instead of the output itself.
+fn redo_stamp(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let parent = parent_target().chain_err(|| "redo-stamp must be run from a .do script")?;
+    let mut ctx = md5::Context::new();
+    io::copy(&mut io::stdin(), &mut ctx).chain_err(|| "Checksum stdin")?;
+    store.set_stamp(&parent, format!("{:x}", ctx.compute()))
+}
+
+// Prints the name of each item we know about for which `pred` holds,
+// relative to the current directory.
+fn list_items<F: FnMut(&Item) -> Result<bool>>(store: &Store, mut pred: F) -> Result<()> {
+    let cwd = env::current_dir()?;
+    for item in store.items()? {
+        if pred(&item)? {
+            println!("{}", item.name.relative_to_dir(&cwd).display());
+        }
+    }
+    Ok(())
+}
+
+// Lists the targets that `redo-ifchange` would rebuild, without building
+// anything.
+fn redo_ood(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let mut memo = HashMap::new();
+    list_items(store, |item| {
+        Ok(item.is_buildable() && item.is_ood(store, &mut memo)?)
+    })
+}
+
+fn redo_targets(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    list_items(store, |item| Ok(item.is_buildable()))
+}
+
+fn redo_sources(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    list_items(store, |item| Ok(!item.is_target()))
+}
+
+// Shows where we look for each target's .do file: `-` for the candidates
+// that don't exist, and `+` for the one we'd use.
+fn redo_whichdo(targets: &[PathBuf]) -> Result<()> {
+    let cwd = Path::new(".").canonicalize()?;
+    for target in targets {
+        let it = Item::new_target(&absolute(target)?);
+        let mut missing = Vec::new();
+        let builder = it.find_builder(&mut missing)?;
+        for candidate in missing {
+            println!("- {}", candidate.relative_to_dir(&cwd).display());
+        }
+        match builder {
+            Some(builder) => println!("+ {}", builder.dofile.relative_to_dir(&cwd).display()),
+            None => return Err(format!("No .do file found for {:?}", target).into()),
+        }
+    }
+    Ok(())
 }
 
-// Sack off the main algorithm bits for now; just implement the minimal redo
-// version. Ie: Rebuild everything. Avoid loops by `.did` files.
-// If a file exists and can't find a `.do` rule, assume it is source.
-//
-// Then extend with redo on mtime change, and redo on mtime+content change.
-//
-fn redo_ifchange(store: &mut Store, targets: &[PathBuf], xtrace: bool) -> Result<()> {
-    // Start off just by rebuilding, like, everything.
+// Replays the stderr of each target's last build, along with that of the
+// targets it built in turn, indented beneath it.
+fn redo_log(store: &mut Store, targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let cwd = env::current_dir()?;
     for target in targets {
-        let it = store
-            .read(&target)?
-            .unwrap_or_else(|| Item::new_target(&target));
+        let target = absolute(target)?;
+        if !exists(&store.log_path(&target))? {
+            return Err(format!("No build log for {:?}", target).into());
+        }
+        replay_log(store, &target, 0, &cwd, &mut HashSet::new())?;
+    }
+    Ok(())
+}
 
-        it.redo(xtrace)?;
+fn replay_log(
+    store: &Store,
+    target: &Path,
+    depth: usize,
+    cwd: &Path,
+    ancestors: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let indent = "  ".repeat(depth);
+    println!("{}{}", indent, target.relative_to_dir(cwd).display());
+    if !ancestors.insert(target.to_owned()) {
+        return Ok(());
     }
 
+    let mut log = Vec::new();
+    if let Some(mut f) = optionally_exists(fs::File::open(store.log_path(target)))? {
+        f.read_to_end(&mut log)?;
+    }
+    if log.last() == Some(&b'\n') {
+        log.pop();
+    }
+    for line in log.split(|&b| b == b'\n').filter(|_| !log.is_empty()) {
+        if line.starts_with(LOG_MARKER) {
+            let child = Path::new(OsStr::from_bytes(&line[LOG_MARKER.len()..]));
+            replay_log(store, child, depth + 1, cwd, ancestors)?;
+        } else {
+            println!("{}  {}", indent, String::from_utf8_lossy(line));
+        }
+    }
+
+    ancestors.remove(target);
+    Ok(())
+}
+
+// Writes the dependency graph reachable from `targets` (or everything we know
+// about) in Graphviz's DOT format. Targets are boxes and sources ellipses;
+// files that don't exist, but whose creation would cause a rebuild, are
+// dashed. Each target has a bold edge to the .do file that built it.
+fn redo_dot(store: &mut Store, targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    let cwd = env::current_dir()?;
+    let name = |p: &Path| dot_quote(&p.relative_to_dir(&cwd).display().to_string());
+    let items = store
+        .items()?
+        .into_iter()
+        .map(|it| (it.name.clone(), it))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut queue = if targets.is_empty() {
+        items.keys().cloned().collect::<VecDeque<_>>()
+    } else {
+        targets
+            .iter()
+            .map(|t| absolute(t))
+            .collect::<Result<VecDeque<_>>>()?
+    };
+    let mut seen = BTreeSet::new();
+
+    println!("digraph redo {{");
+    while let Some(path) = queue.pop_front() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let item = match items.get(&path) {
+            Some(item) if item.is_buildable() => item,
+            Some(item) if !item.is_target() => {
+                println!("  {} [shape=ellipse];", name(&path));
+                continue;
+            }
+            _ if exists(&path)? => {
+                println!("  {} [shape=ellipse];", name(&path));
+                continue;
+            }
+            _ => {
+                println!("  {} [shape=box, style=dashed];", name(&path));
+                continue;
+            }
+        };
+
+        println!("  {} [shape=box];", name(&path));
+        if let Some(ref builder) = item.builder {
+            println!("  {} -> {} [style=bold];", name(&path), name(builder));
+            queue.push_back(builder.clone());
+        }
+        for prereq in item.prereqs.iter() {
+            if Some(&prereq.name) != item.builder.as_ref() {
+                println!("  {} -> {};", name(&path), name(&prereq.name));
+                queue.push_back(prereq.name.clone());
+            }
+        }
+        for prereq in item.prereqs_nonexist.iter() {
+            if seen.insert(prereq.clone()) {
+                println!("  {} [shape=box, style=dashed, color=gray];", name(prereq));
+            }
+            println!(
+                "  {} -> {} [style=dashed, color=gray];",
+                name(&path),
+                name(prereq)
+            );
+        }
+    }
+    println!("}}");
     Ok(())
 }
 
-fn redo_ifcreate(_store: &mut Store, targets: &[PathBuf], _xtrace: bool) -> Result<()> {
-    debug!("redo-ifcreate {:?} ignored", targets);
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// What `redo-json` writes out; this is deliberately separate from `Item`, so
+// that we can change how we store things without breaking anyone's scripts.
+// Paths are relative to `base`.
+#[derive(Serialize, Debug)]
+struct Export<'a> {
+    base: &'a Path,
+    nodes: Vec<ExportNode<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+struct ExportNode<'a> {
+    path: &'a Path,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    checksum: Option<&'a str>,
+    builder: Option<&'a Path>,
+    prereqs: Vec<ExportPrereq<'a>>,
+    ifcreate: Vec<&'a Path>,
+    // Seconds since the epoch.
+    last_built: Option<u64>,
+    // One of "ok" or "failed", if we've ever tried to build this.
+    outcome: Option<&'static str>,
+}
+
+#[derive(Serialize, Debug)]
+struct ExportPrereq<'a> {
+    path: &'a Path,
+    checksum: Option<&'a str>,
+}
+
+// Dumps everything we know about as JSON, for the benefit of other tools.
+fn redo_json(store: &mut Store, _targets: &[PathBuf], _opts: &Options) -> Result<()> {
+    fn rel<'a>(base: &Path, p: &'a Path) -> &'a Path {
+        p.strip_prefix(base).unwrap_or(p)
+    }
+
+    let items = store.items()?;
+    let base = &store.base;
+    let nodes = items
+        .iter()
+        .map(|item| ExportNode {
+            path: rel(base, &item.name),
+            kind: if item.is_target() { "target" } else { "source" },
+            checksum: item.checksum.as_deref(),
+            builder: item.builder.as_ref().map(|b| rel(base, b)),
+            prereqs: item.prereqs
+                .iter()
+                .map(|p| ExportPrereq {
+                    path: rel(base, &p.name),
+                    checksum: p.checksum.as_deref(),
+                })
+                .collect(),
+            ifcreate: item.prereqs_nonexist.iter().map(|p| rel(base, p)).collect(),
+            last_built: item.built_at,
+            outcome: match (item.failed_runid, item.built_at) {
+                (Some(_), _) => Some("failed"),
+                (None, Some(_)) => Some("ok"),
+                (None, None) => None,
+            },
+        })
+        .collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, &Export { base, nodes })?;
+    writeln!(out)?;
     Ok(())
 }
 
@@ -519,11 +2092,43 @@ fn main() {
     env_logger::init();
 
     debug!("✭: {:?}", env::args().collect::<Vec<_>>());
-    let Opt { op, targets } = Opt::from_args();
+    let Opt {
+        op,
+        force,
+        jobs,
+        keep_going,
+        shuffle,
+        shuffle_seed,
+        old_args,
+        targets,
+    } = Opt::from_args();
+
+    let shuffle_seed = shuffle_seed.or_else(|| {
+        env::var("REDO_SHUFFLE_SEED")
+            .ok()
+            .and_then(|s| s.parse().ok())
+    });
+    let shuffle = match shuffle_seed {
+        Some(seed) => Some(seed),
+        None if shuffle => {
+            let seed = rand::random();
+            eprintln!("Shuffling targets with --shuffle-seed={}", seed);
+            Some(seed)
+        }
+        None => None,
+    };
 
-    let xtrace = env::var_os("REDONK_XTRACE").is_some();
+    let opts = Options {
+        xtrace: env::var_os("REDONK_XTRACE").is_some(),
+        // Nested invocations inherit these; see `Builder::build_command`.
+        force: force || env::var_os("REDO_FORCE").is_some(),
+        jobs,
+        keep_going: keep_going || env::var_os("REDO_KEEP_GOING").is_some(),
+        shuffle,
+        old_args: old_args || env::var_os("REDO_OLD_ARGS").is_some(),
+    };
 
-    let code = match run(op, &targets, xtrace) {
+    let code = match run(op, &targets, &opts) {
         Ok(_) => EXIT_SUCCESS,
         Err(e) => {
             eprintln!("Could not build targets: {:?}\n{:?}", targets, e);
@@ -533,7 +2138,7 @@ fn main() {
     process::exit(code);
 }
 
-fn run(op: Operation, targets: &[String], xtrace: bool) -> Result<()> {
+fn run(op: Operation, targets: &[String], opts: &Options) -> Result<()> {
     debug!(
         "op: {:?}; targets: {:?}; in:{:?}",
         op,
@@ -542,15 +2147,70 @@ fn run(op: Operation, targets: &[String], xtrace: bool) -> Result<()> {
     );
     let targets = targets.into_iter().map(PathBuf::from).collect::<Vec<_>>();
 
-    let mut store = Store::new().expect("Store::new");
+    // This only goes looking for .do files, so it has no need of the store.
+    if let Operation::RedoWhichDo = op {
+        return redo_whichdo(&targets).chain_err(|| "redo-whichdo");
+    }
+
+    // Nested invocations inherit the run id from the top-level one; otherwise
+    // the store hands out a new one.
+    let runid = match env::var("REDO_RUNID") {
+        Ok(id) => Some(id.parse().chain_err(|| format!("Parse REDO_RUNID: {:?}", id))?),
+        Err(_) => None,
+    };
+
+    // Nested invocations share the pool of the top-level one, as do we with
+    // make's when we're run from a Makefile.
+    let jobserver = match Jobserver::from_env() {
+        Ok(jobserver) => jobserver,
+        Err(e) => {
+            warn!("Ignoring jobserver from MAKEFLAGS: {}", e);
+            None
+        }
+    };
+    let jobserver = match (jobserver, opts.jobs) {
+        (Some(jobserver), _) => Some(jobserver),
+        (None, Some(jobs)) if jobs > 1 => Some(Jobserver::new(jobs)?),
+        _ => None,
+    };
+    debug!("jobserver: {:?}", jobserver);
+
+    // Queries only look at what earlier builds left behind, so they don't
+    // get a run id of their own, and won't start a project.
+    let mut store = match op {
+        Operation::RedoOod
+        | Operation::RedoTargets
+        | Operation::RedoSources
+        | Operation::RedoLog
+        | Operation::RedoDot
+        | Operation::RedoJson => Store::existing(runid)?,
+        _ => Store::new(runid, jobserver)?,
+    };
     match op {
-        Operation::Redo => redo(&mut store, &targets, xtrace).chain_err(|| "redo"),
+        Operation::Redo => redo(&mut store, &targets, opts).chain_err(|| "redo"),
         Operation::RedoIfChange => {
-            redo_ifchange(&mut store, &targets, xtrace).chain_err(|| "redo-ifchange")
+            redo_ifchange(&mut store, &targets, opts).chain_err(|| "redo-ifchange")
         }
         Operation::RedoIfCreate => {
-            redo_ifcreate(&mut store, &targets, xtrace).chain_err(|| "redo-ifcreate")
+            redo_ifcreate(&mut store, &targets, opts).chain_err(|| "redo-ifcreate")
+        }
+        Operation::RedoAlways => {
+            redo_always(&mut store, &targets, opts).chain_err(|| "redo-always")
+        }
+        Operation::RedoStamp => {
+            redo_stamp(&mut store, &targets, opts).chain_err(|| "redo-stamp")
+        }
+        Operation::RedoOod => redo_ood(&mut store, &targets, opts).chain_err(|| "redo-ood"),
+        Operation::RedoTargets => {
+            redo_targets(&mut store, &targets, opts).chain_err(|| "redo-targets")
         }
+        Operation::RedoSources => {
+            redo_sources(&mut store, &targets, opts).chain_err(|| "redo-sources")
+        }
+        Operation::RedoWhichDo => unreachable!("redo-whichdo has no store"),
+        Operation::RedoLog => redo_log(&mut store, &targets, opts).chain_err(|| "redo-log"),
+        Operation::RedoDot => redo_dot(&mut store, &targets, opts).chain_err(|| "redo-dot"),
+        Operation::RedoJson => redo_json(&mut store, &targets, opts).chain_err(|| "redo-json"),
     }
 }
 
@@ -559,9 +2219,27 @@ fn run(op: Operation, targets: &[String], xtrace: bool) -> Result<()> {
 trait PathExt {
     // This is used to figure out what path a target has relative to a _directory_.
     fn relative_to_dir<P: AsRef<Path>>(&self, base: P) -> PathBuf;
+    // Lexically removes `.` and `..` components; doesn't consult the filesystem.
+    fn normalized(&self) -> PathBuf;
 }
 
 impl<P: AsRef<Path>> PathExt for P {
+    fn normalized(&self) -> PathBuf {
+        let mut result = PathBuf::new();
+        for component in self.as_ref().components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !result.pop() {
+                        result.push("..");
+                    }
+                }
+                other => result.push(other.as_os_str()),
+            }
+        }
+        result
+    }
+
     fn relative_to_dir<P2: AsRef<Path>>(&self, base: P2) -> PathBuf {
         trace!("{:?} relative_to_dir: {:?}", self.as_ref(), base.as_ref());
         assert!(
@@ -620,6 +2298,7 @@ impl<P: AsRef<Path>> PathExt for P {
 #[cfg(test)]
 mod test {
     use super::*;
+    use tempdir::TempDir;
     #[test]
     fn file_suffix_tails_should_return_pathname_tails() {
         let cs = FileSuffixTails::new("foo.bar.baz");
@@ -628,6 +2307,74 @@ mod test {
         assert_eq!(cs.collect::<Vec<_>>(), options);
     }
 
+    fn stack(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn find_wait_cycle_should_follow_other_waiters() {
+        let waits = vec![stack(&["b", "a"]), stack(&["a", "b"])];
+        assert_eq!(
+            find_wait_cycle(&stack(&["a", "b"]), &waits),
+            Some(stack(&["a", "b", "a"]))
+        );
+    }
+
+    #[test]
+    fn find_wait_cycle_should_follow_chains_through_several_targets() {
+        let waits = vec![stack(&["b", "c"]), stack(&["x", "c", "d", "a"])];
+        assert_eq!(
+            find_wait_cycle(&stack(&["top", "a", "b"]), &waits),
+            Some(stack(&["a", "b", "c", "d", "a"]))
+        );
+    }
+
+    #[test]
+    fn find_wait_cycle_should_ignore_unrelated_waiters() {
+        let waits = vec![stack(&["b", "c"]), stack(&["x", "a"])];
+        assert_eq!(find_wait_cycle(&stack(&["a", "b"]), &waits), None);
+    }
+
+    #[test]
+    fn classify_should_treat_existing_unbuilt_target_as_source() {
+        let tmpd = TempDir::new("redonk-classify").expect("tempdir");
+        let name = tmpd.path().join("out");
+        fs::File::create(&name).expect("create");
+
+        assert_eq!(Item::new_target(&name).classify().expect("classify"), Kind::Source);
+    }
+
+    #[test]
+    fn classify_should_not_demote_target_being_built() {
+        let tmpd = TempDir::new("redonk-classify").expect("tempdir");
+        let name = tmpd.path().join("out");
+        fs::File::create(&name).expect("create");
+        let mut item = Item::new_target(&name);
+        item.builder = Some(tmpd.path().join("out.do"));
+
+        assert_eq!(item.classify().expect("classify"), Kind::Target);
+    }
+
+    #[test]
+    fn classify_should_treat_existing_target_that_failed_to_build_as_source() {
+        let tmpd = TempDir::new("redonk-classify").expect("tempdir");
+        let name = tmpd.path().join("out");
+        fs::File::create(&name).expect("create");
+        let mut item = Item::new_target(&name);
+        item.builder = Some(tmpd.path().join("out.do"));
+        item.failed_runid = Some(1);
+
+        assert_eq!(item.classify().expect("classify"), Kind::Source);
+    }
+
+    #[test]
+    fn path_normalized_should_remove_dot_and_dot_dot() {
+        assert_eq!(
+            Path::new("/a/./b/../c").normalized(),
+            Path::new("/a/c")
+        );
+    }
+
     #[test]
     fn path_relativize_should_handle_items_in_same_directory() {
         assert_eq!(
@@ -160,14 +160,23 @@ example!(t_121_defaults_nested, "121-defaults-nested");
 example!(t_130_mode, "130-mode");
 example!(t_140_shuffle, "140-shuffle");
 example!(t_141_keep_going, "141-keep-going");
+example!(t_142_jobs, "142-jobs");
 example!(t_200_shell, "200-shell");
 example!(t_201_fail, "201-fail");
 example!(t_202_del, "202-del");
+example!(t_203_rerun_failed, "203-rerun-failed");
+example!(t_210_cycle, "210-cycle");
 example!(t_220_ifcreate, "220-ifcreate");
+example!(t_230_modified, "230-modified");
 example!(t_250_makedir, "250-makedir");
 example!(t_350_deps, "350-deps");
+example!(t_370_ood, "370-ood");
+example!(t_371_targets, "371-targets");
 example!(t_550_chdir, "550-chdir");
-// example!(t_640_always, "640-always");
-// example!(t_660_stamp, "660-stamp");
+example!(t_640_always, "640-always");
+example!(t_660_stamp, "660-stamp");
+example!(t_700_log, "700-log");
+example!(t_710_dot, "710-dot");
+example!(t_720_json, "720-json");
 example!(t_950_curse, "950-curse");
 // example!(t_999_installer, "999-installer");